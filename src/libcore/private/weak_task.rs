@@ -23,7 +23,8 @@ use private::at_exit::at_exit;
 use private::global::global_data_clone_create;
 use private::finally::Finally;
 use pipes::{Port, Chan, SharedChan, GenericChan, GenericPort,
-            GenericSmartChan, stream};
+            GenericSmartChan, PortOne, ChanOne, oneshot, send_one,
+            recv_one, selecti, stream};
 use task::{Task, task, spawn};
 use task::rt::{task_id, get_task_id};
 use hashmap::linear::LinearMap;
@@ -31,20 +32,19 @@ use ops::Drop;
 
 type ShutdownMsg = ();
 
-// FIXME #4729: This could be a PortOne but I've experienced bugginess
-// with oneshot pipes and try_send
-pub unsafe fn weaken_task(f: &fn(Port<ShutdownMsg>)) {
+pub unsafe fn weaken_task(f: &fn(PortOne<ShutdownMsg>, Chan<ShutdownMsg>)) {
     let service = global_data_clone_create(global_data_key,
                                            create_global_service);
-    let (shutdown_port, shutdown_chan) = stream::<ShutdownMsg>();
-    let shutdown_port = ~mut Some(shutdown_port);
+    let (shutdown_port, shutdown_chan) = oneshot::<ShutdownMsg>();
+    let (ack_port, ack_chan) = stream::<ShutdownMsg>();
+    let ack_chan = ~mut Some(ack_chan);
     let task = get_task_id();
     // Expect the weak task service to be alive
-    assert service.try_send(RegisterWeakTask(task, shutdown_chan));
+    assert service.try_send(RegisterWeakTask(task, shutdown_chan, ack_port));
     unsafe { rust_dec_kernel_live_count(); }
     do fn&() {
-        let shutdown_port = swap_unwrap(&mut *shutdown_port);
-        f(shutdown_port)
+        let ack_chan = swap_unwrap(&mut *ack_chan);
+        f(shutdown_port, ack_chan)
     }.finally || {
         unsafe { rust_inc_kernel_live_count(); }
         // Service my have already exited
@@ -58,9 +58,29 @@ type TaskHandle = task_id;
 fn global_data_key(_v: WeakTaskService) { }
 
 enum ServiceMsg {
-    RegisterWeakTask(TaskHandle, Chan<ShutdownMsg>),
+    RegisterWeakTask(TaskHandle, ChanOne<ShutdownMsg>, Port<ShutdownMsg>),
     UnregisterWeakTask(TaskHandle),
-    Shutdown
+    QueryWeakTasks(Chan<~[TaskHandle]>),
+    // The deadline, in nanoseconds, to wait for outstanding acks
+    // before giving up on a clean shutdown. `None` waits forever.
+    Shutdown(Option<u64>)
+}
+
+/// The number of weak tasks currently registered with the global
+/// weak task service.
+pub fn weak_task_count() -> uint {
+    list_weak_tasks().len()
+}
+
+/// The handles of the weak tasks currently registered with the
+/// global weak task service.
+pub fn list_weak_tasks() -> ~[TaskHandle] {
+    let service = global_data_clone_create(global_data_key,
+                                           create_global_service);
+    let (port, chan) = stream::<~[TaskHandle]>();
+    // Expect the weak task service to be alive
+    assert service.try_send(QueryWeakTasks(chan));
+    port.recv()
 }
 
 fn create_global_service() -> ~WeakTaskService {
@@ -89,7 +109,7 @@ fn create_global_service() -> ~WeakTaskService {
 
     do at_exit {
         debug!("shutting down weak task service");
-        chan.send(Shutdown);
+        chan.send(Shutdown(None));
     }
 
     return ~chan_clone;
@@ -98,37 +118,114 @@ fn create_global_service() -> ~WeakTaskService {
 fn run_weak_task_service(port: Port<ServiceMsg>) {
 
     let mut shutdown_map = LinearMap::new();
+    let mut ack_map = LinearMap::new();
+    let mut deadline = None;
 
     loop {
         match port.recv() {
-            RegisterWeakTask(task, shutdown_chan) => {
+            RegisterWeakTask(task, shutdown_chan, ack_port) => {
                 let previously_unregistered =
                     shutdown_map.insert(task, shutdown_chan);
                 assert previously_unregistered;
+                let previously_unregistered =
+                    ack_map.insert(task, ack_port);
+                assert previously_unregistered;
             }
             UnregisterWeakTask(task) => {
                 match shutdown_map.pop(&task) {
                     Some(shutdown_chan) => {
                         // Oneshot pipes must send, even though
                         // nobody will receive this
-                        shutdown_chan.send(());
+                        send_one(shutdown_chan, ());
                     }
                     None => fail!()
                 }
+                ack_map.remove(&task);
+            }
+            QueryWeakTasks(reply_chan) => {
+                let mut tasks = ~[];
+                for shutdown_map.each_key |task| {
+                    tasks.push(*task);
+                }
+                reply_chan.send(tasks);
+            }
+            Shutdown(d) => {
+                deadline = d;
+                break
             }
-            Shutdown => break
         }
     }
 
     do shutdown_map.consume |_, shutdown_chan| {
         // Weak task may have already exited
-        shutdown_chan.send(());
+        send_one(shutdown_chan, ());
+    }
+
+    // Give the weak tasks a chance to flush their state (logs,
+    // sockets, ...) before the runtime is torn out from under them.
+    wait_for_acks(ack_map, deadline);
+}
+
+// Block until every outstanding weak task has acked the shutdown
+// signal, or until `deadline` nanoseconds have elapsed, whichever
+// comes first. A task that exits (fails or otherwise) without acking
+// is simply dropped: once its ack_port closes, `select` stops waiting
+// on it and we move on rather than calling the panicking `recv`.
+//
+// This blocks on a real `select` over every still-outstanding ack
+// port instead of spinning a peek/yield loop: a deadline, when given,
+// is just one more port in that same select set, fed by a dedicated
+// timer task that blocks on a real sleep rather than busy-polling the
+// clock.
+fn wait_for_acks(ack_map: LinearMap<TaskHandle, Port<ShutdownMsg>>,
+                 deadline: Option<u64>) {
+    let mut pending = ~[];
+    do ack_map.consume |_, ack_port| {
+        pending.push(ack_port);
+    }
+
+    let mut timeout_index = do deadline.map |d| {
+        pending.push(spawn_deadline_port(*d));
+        pending.len() - 1
+    };
+
+    // Once only the deadline port (if any) is left, every ack is in.
+    while pending.len() > (if timeout_index.is_some() { 1 } else { 0 }) {
+        let ready = selecti(pending);
+
+        match timeout_index {
+            Some(t) if t == ready => break,
+            _ => ()
+        }
+
+        // `try_recv` never fails, even if the sender was dropped
+        // without sending (the weak task died before acking) --
+        // either way we're done waiting on it.
+        pending[ready].try_recv();
+        pending.remove(ready);
+
+        timeout_index = do timeout_index.map |t| {
+            if ready < *t { *t - 1 } else { *t }
+        };
+    }
+}
+
+// A port that receives a single message once `deadline_ns` nanoseconds
+// have elapsed, used to bound `wait_for_acks` without it having to
+// poll the clock itself.
+fn spawn_deadline_port(deadline_ns: u64) -> Port<ShutdownMsg> {
+    let (port, chan) = stream::<ShutdownMsg>();
+    do task().unlinked().spawn {
+        unsafe { rust_sleep_ns(deadline_ns); }
+        chan.send(());
     }
+    port
 }
 
 extern {
     unsafe fn rust_inc_kernel_live_count();
     unsafe fn rust_dec_kernel_live_count();
+    unsafe fn rust_sleep_ns(ns: u64);
 }
 
 #[test]
@@ -136,7 +233,8 @@ fn test_simple() {
     let (port, chan) = stream();
     do spawn {
         unsafe {
-            do weaken_task |_signal| {
+            do weaken_task |_signal, ack| {
+                ack.send(());
             }
         }
         chan.send(());
@@ -149,9 +247,11 @@ fn test_weak_weak() {
     let (port, chan) = stream();
     do spawn {
         unsafe {
-            do weaken_task |_signal| {
+            do weaken_task |_signal, ack| {
+                ack.send(());
             }
-            do weaken_task |_signal| {
+            do weaken_task |_signal, ack| {
+                ack.send(());
             }
         }
         chan.send(());
@@ -163,8 +263,9 @@ fn test_weak_weak() {
 fn test_wait_for_signal() {
     do spawn {
         unsafe {
-            do weaken_task |signal| {
-                signal.recv();
+            do weaken_task |signal, ack| {
+                recv_one(signal);
+                ack.send(());
             }
         }
     }
@@ -176,14 +277,49 @@ fn test_wait_for_signal_many() {
     for uint::range(0, 100) |_| {
         do spawn {
             unsafe {
-                do weaken_task |signal| {
-                    signal.recv();
+                do weaken_task |signal, ack| {
+                    recv_one(signal);
+                    ack.send(());
                 }
             }
         }
     }
 }
 
+#[test]
+fn test_wait_for_acks_deadline() {
+    let mut ack_map = LinearMap::new();
+    let (ack_port, _ack_chan) = stream::<ShutdownMsg>();
+    // Nobody ever sends on `_ack_chan`, so without the deadline this
+    // would hang forever; `wait_for_acks` must give up once it elapses.
+    ack_map.insert(get_task_id(), ack_port);
+    wait_for_acks(ack_map, Some(1_000_000)); // 1ms
+}
+
+#[test]
+fn test_query_weak_tasks() {
+    use pipes::select2i;
+    use either::{Left, Right};
+
+    let (port, chan) = stream();
+    let (regport, regchan) = stream();
+    do spawn {
+        unsafe {
+            do weaken_task |signal, ack| {
+                regchan.send(());
+                match select2i(&port, &signal) {
+                    Left(*) => (),
+                    Right(*) => fail!()
+                }
+                ack.send(());
+            }
+        }
+    }
+    regport.recv();
+    assert weak_task_count() >= 1;
+    chan.send(());
+}
+
 #[test]
 fn test_select_stream_and_oneshot() {
     use pipes::select2i;
@@ -193,11 +329,12 @@ fn test_select_stream_and_oneshot() {
     let (waitport, waitchan) = stream();
     do spawn {
         unsafe {
-            do weaken_task |signal| {
+            do weaken_task |signal, ack| {
                 match select2i(&port, &signal) {
                     Left(*) => (),
                     Right(*) => fail!()
                 }
+                ack.send(());
             }
         }
         waitchan.send(());
@@ -205,4 +342,3 @@ fn test_select_stream_and_oneshot() {
     chan.send(());
     waitport.recv();
 }
-